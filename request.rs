@@ -3,10 +3,10 @@
  * wants us to perform.
  */
 
-use std::{cast, libc, os, ptr, sys, vec};
+use std::{cast, cmp, libc, os, ptr, sys, vec};
 use std::io::fd_t;
 use std::libc::{dev_t, c_int, c_void, mode_t, off_t, size_t, ssize_t};
-use std::libc::{EIO, ENOSYS, EPROTO, ERANGE};
+use std::libc::{EINVAL, EIO, ENOSYS, EPROTO, ERANGE};
 use argument::ArgumentIterator;
 use Filesystem;
 use native::*;
@@ -32,11 +32,208 @@ impl Sendable for fuse_getxattr_out { }
 impl Sendable for fuse_lk_out { }
 impl Sendable for fuse_init_out { }
 impl Sendable for fuse_bmap_out { }
+impl Sendable for fuse_ioctl_out { }
+
+/// Source of request bytes for `dispatch`. `DevFuseChannel` below reads
+/// from `/dev/fuse`; other transports can implement this over a virtio queue or socket.
+pub trait ChannelReader {
+	/// Read the next request into the buffer described by `buf`/`capacity`,
+	/// returning the number of bytes read
+	unsafe fn read_request (&mut self, buf: *mut c_void, capacity: size_t) -> ssize_t;
+}
+
+pub trait ChannelWriter {
+	/// Send a reply made up of the given iovecs
+	unsafe fn send_reply (&mut self, iov: *iovec, iovlen: c_int) -> ssize_t;
+}
+
+/// The default channel: the kernel's `/dev/fuse` character device
+pub struct DevFuseChannel {
+	priv fd: fd_t,
+}
+
+impl DevFuseChannel {
+	pub fn new (fd: fd_t) -> DevFuseChannel {
+		DevFuseChannel { fd: fd }
+	}
+}
+
+impl ChannelReader for DevFuseChannel {
+	#[fixed_stack_segment]
+	unsafe fn read_request (&mut self, buf: *mut c_void, capacity: size_t) -> ssize_t {
+		// The kernel driver makes sure that we get exactly one request per read.
+		libc::read(self.fd, buf, capacity)
+	}
+}
+
+impl ChannelWriter for DevFuseChannel {
+	#[fixed_stack_segment]
+	unsafe fn send_reply (&mut self, iov: *iovec, iovlen: c_int) -> ssize_t {
+		writev(self.fd, iov, iovlen)
+	}
+}
+
+/// Combined reply for FUSE_CREATE: a fuse_entry_out immediately followed
+/// by a fuse_open_out in the same writev.
+struct CreateReply {
+	entry: fuse_entry_out,
+	open: fuse_open_out,
+}
+
+impl Sendable for CreateReply {
+	fn as_iovecs<T> (&self, f: &fn(&[iovec]) -> T) -> T {
+		do self.entry.as_iovecs |entry_iovs| {
+			do self.open.as_iovecs |open_iovs| {
+				f(entry_iovs.to_owned() + open_iovs)
+			}
+		}
+	}
+}
+
+/// Reply to FUSE_IOCTL: either a completed call, or a retry listing the
+/// iovec ranges the kernel should fetch/deliver before calling again.
+pub enum IoctlReply {
+	Done(i32, ~[u8]),
+	Retry(~[fuse_ioctl_iovec], ~[fuse_ioctl_iovec]),
+}
+
+/// Combined reply for a completed FUSE_IOCTL: a fuse_ioctl_out immediately
+/// followed by the output bytes.
+struct IoctlDataReply {
+	header: fuse_ioctl_out,
+	data: ~[u8],
+}
+
+impl Sendable for IoctlDataReply {
+	fn as_iovecs<T> (&self, f: &fn(&[iovec]) -> T) -> T {
+		do self.header.as_iovecs |header_iovs| {
+			f(header_iovs.to_owned() + [iovec {
+				iov_base: vec::raw::to_ptr(self.data) as *c_void,
+				iov_len: self.data.len() as size_t,
+			}])
+		}
+	}
+}
+
+/// Combined reply for a FUSE_IOCTL_RETRY: a fuse_ioctl_out immediately
+/// followed by the in and out iovec arrays the kernel should resubmit.
+struct IoctlRetryReply {
+	header: fuse_ioctl_out,
+	in_iovs: ~[fuse_ioctl_iovec],
+	out_iovs: ~[fuse_ioctl_iovec],
+}
+
+impl Sendable for IoctlRetryReply {
+	fn as_iovecs<T> (&self, f: &fn(&[iovec]) -> T) -> T {
+		do self.header.as_iovecs |header_iovs| {
+			f(header_iovs.to_owned() + [
+				iovec {
+					iov_base: vec::raw::to_ptr(self.in_iovs) as *c_void,
+					iov_len: (self.in_iovs.len() * sys::size_of::<fuse_ioctl_iovec>()) as size_t,
+				},
+				iovec {
+					iov_base: vec::raw::to_ptr(self.out_iovs) as *c_void,
+					iov_len: (self.out_iovs.len() * sys::size_of::<fuse_ioctl_iovec>()) as size_t,
+				},
+			])
+		}
+	}
+}
+
+/// A reader over the bytes of a FUSE_WRITE request, letting a filesystem
+/// splice them straight into a backing file descriptor.
+pub struct Reader<'a> {
+	priv data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn new (data: &'a [u8]) -> Reader<'a> {
+		Reader { data: data }
+	}
+
+	/// Write up to `count` bytes of the request payload to `fd` at `offset`
+	#[fixed_stack_segment]
+	pub fn read_to (&mut self, fd: fd_t, count: size_t, offset: off_t) -> Result<size_t, c_int> {
+		let count = cmp::min(count as uint, self.data.len());
+		let res = do self.data.slice_to(count).as_imm_buf |ptr, _| {
+			unsafe { libc::pwrite(fd, ptr as *c_void, count as size_t, offset) }
+		};
+		if res < 0 { Err(os::errno() as c_int) } else { Ok(res as size_t) }
+	}
+
+	/// The raw bytes of the request payload, for the default write() fallback
+	/// that copies them itself instead of writing them to a file descriptor
+	pub fn as_slice (&self) -> &'a [u8] {
+		self.data
+	}
+}
+
+/// A writer for a FUSE_READ reply, letting a filesystem splice file contents
+/// straight from a file descriptor. The fuse_out_header is only emitted on flush.
+pub struct Writer<'a, C> {
+	priv channel: &'a mut C,
+	priv unique: u64,
+	priv buf: ~[u8],
+}
+
+impl<'a, C: ChannelWriter> Writer<'a, C> {
+	fn new (channel: &'a mut C, unique: u64) -> Writer<'a, C> {
+		Writer { channel: channel, unique: unique, buf: ~[] }
+	}
+
+	/// Splice up to `count` bytes from `fd` at `offset` straight into the reply
+	/// buffer, returning the number of bytes actually read
+	#[fixed_stack_segment]
+	pub fn write_from (&mut self, fd: fd_t, count: size_t, offset: off_t) -> Result<size_t, c_int> {
+		let start = self.buf.len();
+		self.buf.reserve(start + count as uint);
+		unsafe { vec::raw::set_len(&mut self.buf, start + count as uint); }
+		let res = do self.buf.mut_slice_from(start).as_mut_buf |ptr, _| {
+			unsafe { libc::pread(fd, ptr as *mut c_void, count, offset) }
+		};
+		if res < 0 {
+			let err = os::errno() as c_int;
+			unsafe { vec::raw::set_len(&mut self.buf, start); }
+			Err(err)
+		} else {
+			unsafe { vec::raw::set_len(&mut self.buf, start + res as uint); }
+			Ok(res as size_t)
+		}
+	}
+
+	/// Append an already-produced buffer to the reply, for the default
+	/// read_to() fallback that copies the data itself instead of splicing
+	/// it from a file descriptor
+	pub fn write (&mut self, data: &[u8]) -> Result<size_t, c_int> {
+		self.buf.push_all(data);
+		Ok(data.len() as size_t)
+	}
+
+	/// Emit the fuse_out_header followed by the buffered reply data. On error,
+	/// the header carries the (negated) errno and no body, matching `send`.
+	#[fixed_stack_segment]
+	fn flush (&mut self, err: c_int) {
+		let body_len = if err == 0 { self.buf.len() } else { 0 };
+		let header = fuse_out_header {
+			len: sys::size_of::<fuse_out_header>() as u32 + body_len as u32,
+			error: -err as i32,
+			unique: self.unique,
+		};
+		let mut iov = ~[
+			iovec { iov_base: ptr::to_unsafe_ptr(&header) as *c_void, iov_len: sys::size_of_val(&header) as size_t },
+		];
+		if err == 0 {
+			iov.push(iovec { iov_base: vec::raw::to_ptr(self.buf) as *c_void, iov_len: self.buf.len() as size_t });
+		}
+		do iov.as_imm_buf |iovptr, iovlen| {
+			unsafe { self.channel.send_reply(iovptr, iovlen as c_int); }
+		}
+	}
+}
 
 /// Request data structure
 pub struct Request {
 	priv data: ~[u8],
-	priv fd: Option<fd_t>,
 }
 
 impl Request {
@@ -44,20 +241,17 @@ impl Request {
 	pub fn new () -> ~Request {
 		~Request {
 			data: vec::with_capacity(MAX_WRITE_SIZE as uint + 4096),
-			fd: None,
 		}
 	}
 
-	/// Read the next request from the given fd (channel to kernel driver)
+	/// Read the next request from the given channel
 	#[fixed_stack_segment]
-	pub fn read (&mut self, fd: fd_t) -> Result<(), c_int> {
+	pub fn read<C: ChannelReader> (&mut self, channel: &mut C) -> Result<(), c_int> {
 		assert!(self.data.capacity() >= MAX_WRITE_SIZE as uint + 4096);
 		let capacity = self.data.capacity();
 		self.data.clear();
-		self.fd = Some(fd);
 		let res = do self.data.as_mut_buf |dataptr, _| {
-			// The kernel driver makes sure that we get exactly one request per read.
-			unsafe { libc::read(fd, dataptr as *mut c_void, capacity as size_t) }
+			unsafe { channel.read_request(dataptr as *mut c_void, capacity as size_t) }
 		};
 		if res < 0 {
 			Err(os::errno() as c_int)
@@ -70,11 +264,12 @@ impl Request {
 		}
 	}
 
-	/// Dispatch request to the given filesystem.
+	/// Dispatch request to the given filesystem, reading request data from `self`
+	/// and sending replies back over `channel`.
 	/// This parses a previously read request, calls the appropriate
 	/// filesystem operation method and sends back the returned reply
 	/// to the kernel
-	pub fn dispatch<FS: Filesystem> (&self, se: &mut Session<FS>) {
+	pub fn dispatch<FS: Filesystem, C: ChannelWriter> (&self, se: &mut Session<FS>, channel: &mut C) {
 		// Every request begins with a fuse_in_header struct followed by arbitrary
 		// data depending on which opcode it contains
 		assert!(self.data.len() >= sys::size_of::<fuse_in_header>());
@@ -83,6 +278,13 @@ impl Request {
 		// FIXME: Ugly (and unsafe) way of conversion to enum. Fix this, once Rust can convert
 		// integers to enums somehow. See https://github.com/mozilla/rust/issues/3868
 		let opcode: fuse_opcode = unsafe { cast::transmute(header.opcode as uint) };
+		// Track this request as in-flight so a later FUSE_INTERRUPT can find it. In the
+		// current synchronous dispatch loop a request is always gone from `pending` again
+		// before the next one is read, so FUSE_INTERRUPT can never actually observe it here
+		// -- this registry is scaffolding for the multi-threaded/async dispatcher that's the
+		// whole reason FUSE_INTERRUPT needs handling in the first place. It becomes load-
+		// bearing once dispatch() can run concurrently for more than one request at a time.
+		se.pending.insert(header.unique);
 		match opcode {
 			// Filesystem initialization
 			FUSE_INIT => {
@@ -91,7 +293,8 @@ impl Request {
 				// We don't support ABI versions before 7.6
 				if arg.major < 7 || (arg.major < 7 && arg.minor < 6) {
 					error2!("Unsupported FUSE ABI version {:u}.{:u}", arg.major, arg.minor);
-					self.reply_error(EPROTO);
+					self.reply_error(channel, EPROTO);
+					se.pending.remove(&header.unique);
 					return;
 				}
 				// Remember ABI version supported by kernel
@@ -100,7 +303,8 @@ impl Request {
 				// Call filesystem init method and give it a chance to return an error
 				let res = se.filesystem.init();
 				if res.is_err() {
-					self.reply_error(res.unwrap_err());
+					self.reply_error(channel, res.unwrap_err());
+					se.pending.remove(&header.unique);
 					return;
 				}
 				// Reply with our desired version and settings. If the kernel supports a
@@ -116,32 +320,46 @@ impl Request {
 				};
 				debug2!("INIT({:u}) response: ABI {:u}.{:u}, flags {:#x}, max readahead {:u}, max write {:u}", header.unique, reply.major, reply.minor, reply.flags, reply.max_readahead, reply.max_write);
 				se.initialized = true;
-				self.reply(Ok(reply));
+				self.reply(channel, Ok(reply));
+			},
+			// FUSE_INTERRUPT takes no reply regardless of init/destroy state, so it
+			// must be handled before the guard arms below can catch it and reply EIO
+			FUSE_INTERRUPT => {
+				let arg: &fuse_interrupt_in = data.fetch();
+				debug2!("INTERRUPT({:u}) unique {:u}", header.unique, arg.unique);
+				if se.pending.contains(&arg.unique) {
+					// The target request is still running: ask the filesystem to cancel it.
+					// Per the protocol, the interrupted request should eventually reply EINTR.
+					se.filesystem.interrupt(arg.unique);
+				} else {
+					// The target request has already completed, so there's nothing to do
+					debug2!("INTERRUPT({:u}) unique {:u} not found, ignoring", header.unique, arg.unique);
+				}
+				// FUSE_INTERRUPT takes no reply: the kernel doesn't track its unique
+				// in the processing queue, so replying to it yields a spurious error
 			},
 			// Any operation is invalid before initialization
 			_ if !se.initialized => {
 				warn2!("Ignoring FUSE operation {:u} before init", header.opcode);
-				self.reply_error(EIO);
+				self.reply_error(channel, EIO);
 			},
 			// Filesystem destroyed
 			FUSE_DESTROY => {
 				debug2!("DESTROY({:u})", header.unique);
 				se.filesystem.destroy();
 				se.destroyed = true;
-				self.reply(Ok(()));
+				self.reply(channel, Ok(()));
 			}
 			// Any operation is invalid after destroy
 			_ if se.destroyed => {
 				warn2!("Ignoring FUSE operation {:u} after destroy", header.opcode);
-				self.reply_error(EIO);
+				self.reply_error(channel, EIO);
 			}
 
-			// TODO: FUSE_INTERRUPT,
-
 			FUSE_LOOKUP => {
 				let name = data.fetch_str();
 				debug2!("LOOKUP({:u}) parent {:#018x}, name {:s}", header.unique, header.nodeid, name);
-				self.reply(se.filesystem.lookup(header.nodeid, name));
+				self.reply(channel, se.filesystem.lookup(header.nodeid, name));
 			},
 			FUSE_FORGET => {
 				let arg: &fuse_forget_in = data.fetch();
@@ -150,118 +368,123 @@ impl Request {
 			},
 			FUSE_GETATTR => {
 				debug2!("GETATTR({:u}) ino {:#018x}", header.unique, header.nodeid);
-				self.reply(se.filesystem.getattr(header.nodeid));
+				self.reply(channel, se.filesystem.getattr(header.nodeid));
 			},
 			FUSE_SETATTR => {
 				let arg: &fuse_setattr_in = data.fetch();
 				debug2!("SETATTR({:u}) ino {:#018x}, valid {:#x}", header.unique, header.nodeid, arg.valid);
-				self.reply(se.filesystem.setattr(header.nodeid, arg));
+				self.reply(channel, se.filesystem.setattr(header.nodeid, arg));
 			},
 			FUSE_READLINK => {
 				debug2!("READLINK({:u}) ino {:#018x}", header.unique, header.nodeid);
-				self.reply(se.filesystem.readlink(header.nodeid));
+				self.reply(channel, se.filesystem.readlink(header.nodeid));
 			},
 			FUSE_MKNOD => {
 				let arg: &fuse_mknod_in = data.fetch();
 				let name = data.fetch_str();
 				debug2!("MKNOD({:u}) parent {:#018x}, name {:s}, mode {:#05o}, rdev {:u}", header.unique, header.nodeid, name, arg.mode, arg.rdev);
-				self.reply(se.filesystem.mknod(header.nodeid, name, arg.mode as mode_t, arg.rdev as dev_t));
+				self.reply(channel, se.filesystem.mknod(header.nodeid, name, arg.mode as mode_t, arg.rdev as dev_t));
 			},
 			FUSE_MKDIR => {
 				let arg: &fuse_mkdir_in = data.fetch();
 				let name = data.fetch_str();
 				debug2!("MKDIR({:u}) parent {:#018x}, name {:s}, mode {:#05o}", header.unique, header.nodeid, name, arg.mode);
-				self.reply(se.filesystem.mkdir(header.nodeid, name, arg.mode as mode_t));
+				self.reply(channel, se.filesystem.mkdir(header.nodeid, name, arg.mode as mode_t));
 			},
 			FUSE_UNLINK => {
 				let name = data.fetch_str();
 				debug2!("UNLINK({:u}) parent {:#018x}, name {:s}", header.unique, header.nodeid, name);
-				self.reply(se.filesystem.unlink(header.nodeid, name));
+				self.reply(channel, se.filesystem.unlink(header.nodeid, name));
 			},
 			FUSE_RMDIR => {
 				let name = data.fetch_str();
 				debug2!("RMDIR({:u}) parent {:#018x}, name {:s}", header.unique, header.nodeid, name);
-				self.reply(se.filesystem.rmdir(header.nodeid, name));
+				self.reply(channel, se.filesystem.rmdir(header.nodeid, name));
 			},
 			FUSE_SYMLINK => {
 				let name = data.fetch_str();
 				let link = data.fetch_str();
 				debug2!("SYMLINK({:u}) parent {:#018x}, name {:s}, link {:s}", header.unique, header.nodeid, name, link);
-				self.reply(se.filesystem.symlink(header.nodeid, name, link));
+				self.reply(channel, se.filesystem.symlink(header.nodeid, name, link));
 			},
 			FUSE_RENAME => {
 				let arg: &fuse_rename_in = data.fetch();
 				let name = data.fetch_str();
 				let newname = data.fetch_str();
 				debug2!("RENAME({:u}) parent {:#018x}, name {:s}, newparent {:#018x}, newname {:s}", header.unique, header.nodeid, name, arg.newdir, newname);
-				self.reply(se.filesystem.rename(header.nodeid, name, arg.newdir, newname));
+				self.reply(channel, se.filesystem.rename(header.nodeid, name, arg.newdir, newname));
 			},
 			FUSE_LINK => {
 				let arg: &fuse_link_in = data.fetch();
 				let newname = data.fetch_str();
 				debug2!("LINK({:u}) ino {:#018x}, newparent {:#018x}, newname {:s}", header.unique, arg.oldnodeid, header.nodeid, newname);
-				self.reply(se.filesystem.link(arg.oldnodeid, header.nodeid, newname));
+				self.reply(channel, se.filesystem.link(arg.oldnodeid, header.nodeid, newname));
 			},
 			FUSE_OPEN => {
 				let arg: &fuse_open_in = data.fetch();
 				debug2!("OPEN({:u}) ino {:#018x}, flags {:#x}, mode {:#x}", header.unique, header.nodeid, arg.flags, arg.mode);
-				self.reply(se.filesystem.open(header.nodeid, arg.flags as uint));
+				self.reply(channel, se.filesystem.open(header.nodeid, arg.flags as uint));
 			},
 			FUSE_READ => {
 				let arg: &fuse_read_in = data.fetch();
 				debug2!("READ({:u}) ino {:#018x}, fh {:u}, offset {:u}, size {:u}", header.unique, header.nodeid, arg.fh, arg.offset, arg.size);
-				self.reply(se.filesystem.read(header.nodeid, arg.fh, arg.offset as off_t, arg.size as size_t));
+				let mut writer = Writer::new(channel, header.unique);
+				match se.filesystem.read_to(header.nodeid, arg.fh, arg.offset as off_t, arg.size as size_t, &mut writer) {
+					Ok(()) => writer.flush(0),
+					Err(err) => writer.flush(err),
+				}
 			},
 			FUSE_WRITE => {
 				let arg: &fuse_write_in = data.fetch();
-				let data = data.fetch_data();
-				assert!(data.len() == arg.size as uint);
+				let payload = data.fetch_data();
+				assert!(payload.len() == arg.size as uint);
 				debug2!("WRITE({:u}) ino {:#018x}, fh {:u}, offset {:u}, size {:u}, flags {:#x}", header.unique, header.nodeid, arg.fh, arg.offset, arg.size, arg.write_flags);
-				self.reply(se.filesystem.write(header.nodeid, arg.fh, arg.offset as off_t, data, arg.write_flags as uint).and_then(|written| {
+				let mut reader = Reader::new(payload);
+				self.reply(channel, se.filesystem.write_from(header.nodeid, arg.fh, arg.offset as off_t, &mut reader, arg.size as size_t, arg.write_flags as uint).and_then(|written| {
 					Ok(~fuse_write_out { size: written as u32, padding: 0 })
 				}));
 			},
 			FUSE_FLUSH => {
 				let arg: &fuse_flush_in = data.fetch();
 				debug2!("FLUSH({:u}) ino {:#018x}, fh {:u}, lock owner {:u}", header.unique, header.nodeid, arg.fh, arg.lock_owner);
-				self.reply(se.filesystem.flush(header.nodeid, arg.fh, arg.lock_owner));
+				self.reply(channel, se.filesystem.flush(header.nodeid, arg.fh, arg.lock_owner));
 			},
 			FUSE_RELEASE => {
 				let arg: &fuse_release_in = data.fetch();
 				let flush = match arg.release_flags & FUSE_RELEASE_FLUSH { 0 => false, _ => true };
 				debug2!("RELEASE({:u}) ino {:#018x}, fh {:u}, flags {:#x}, release flags {:#x}, lock owner {:u}", header.unique, header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
-				self.reply(se.filesystem.release(header.nodeid, arg.fh, arg.flags as uint, arg.lock_owner, flush));
+				self.reply(channel, se.filesystem.release(header.nodeid, arg.fh, arg.flags as uint, arg.lock_owner, flush));
 			},
 			FUSE_FSYNC => {
 				let arg: &fuse_fsync_in = data.fetch();
 				let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
 				debug2!("FSYNC({:u}) ino {:#018x}, fh {:u}, flags {:#x}", header.unique, header.nodeid, arg.fh, arg.fsync_flags);
-				self.reply(se.filesystem.fsync(header.nodeid, arg.fh, datasync));
+				self.reply(channel, se.filesystem.fsync(header.nodeid, arg.fh, datasync));
 			},
 			FUSE_OPENDIR => {
 				let arg: &fuse_open_in = data.fetch();
 				debug2!("OPENDIR({:u}) ino {:#018x}, flags {:#x}, mode {:#x}", header.unique, header.nodeid, arg.flags, arg.mode);
-				self.reply(se.filesystem.opendir(header.nodeid, arg.flags as uint));
+				self.reply(channel, se.filesystem.opendir(header.nodeid, arg.flags as uint));
 			},
 			FUSE_READDIR => {
 				let arg: &fuse_read_in = data.fetch();
 				debug2!("READDIR({:u}) ino {:#018x}, fh {:u}, offset {:u}, size {:u}", header.unique, header.nodeid, arg.fh, arg.offset, arg.size);
-				self.reply(se.filesystem.readdir(header.nodeid, arg.fh, arg.offset as off_t, DirBuffer::new(arg.size as uint)));
+				self.reply(channel, se.filesystem.readdir(header.nodeid, arg.fh, arg.offset as off_t, DirBuffer::new(arg.size as uint)));
 			},
 			FUSE_RELEASEDIR => {
 				let arg: &fuse_release_in = data.fetch();
 				debug2!("RELEASEDIR({:u}) ino {:#018x}, fh {:u}, flags {:#x}, release flags {:#x}, lock owner {:u}", header.unique, header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
-				self.reply(se.filesystem.releasedir(header.nodeid, arg.fh, arg.flags as uint));
+				self.reply(channel, se.filesystem.releasedir(header.nodeid, arg.fh, arg.flags as uint));
 			},
 			FUSE_FSYNCDIR => {
 				let arg: &fuse_fsync_in = data.fetch();
 				let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
 				debug2!("FSYNCDIR({:u}) ino {:#018x}, fh {:u}, flags {:#x}", header.unique, header.nodeid, arg.fh, arg.fsync_flags);
-				self.reply(se.filesystem.fsyncdir(header.nodeid, arg.fh, datasync));
+				self.reply(channel, se.filesystem.fsyncdir(header.nodeid, arg.fh, datasync));
 			},
 			FUSE_STATFS => {
 				debug2!("STATFS({:u}) ino {:#018x}", header.unique, header.nodeid);
-				self.reply(se.filesystem.statfs(header.nodeid));
+				self.reply(channel, se.filesystem.statfs(header.nodeid));
 			},
 			FUSE_SETXATTR => {
 				let arg: &fuse_setxattr_in = data.fetch();
@@ -270,7 +493,7 @@ impl Request {
 				assert!(value.len() == arg.size as uint);
 				// FIXME: arg.position exists on OS X only, use 0 on other OS
 				debug2!("SETXATTR({:u}) ino {:#018x}, name {:s}, size {:u}, flags {:#x}", header.unique, header.nodeid, name, arg.size, arg.flags);
-				self.reply(se.filesystem.setxattr(header.nodeid, name, value, arg.flags as uint, arg.position as off_t));
+				self.reply(channel, se.filesystem.setxattr(header.nodeid, name, value, arg.flags as uint, arg.position as off_t));
 			},
 			FUSE_GETXATTR => {
 				let arg: &fuse_getxattr_in = data.fetch();
@@ -278,69 +501,120 @@ impl Request {
 				debug2!("GETXATTR({:u}) ino {:#018x}, name {:s}, size {:u}", header.unique, header.nodeid, name, arg.size);
 				match se.filesystem.getxattr(header.nodeid, name) {
 					// If arg.size is zero, the size of the value should be sent with fuse_getxattr_out
-					Ok(ref value) if arg.size == 0 => self.reply(Ok(fuse_getxattr_out { size: value.len() as u32, padding: 0 })),
+					Ok(ref value) if arg.size == 0 => self.reply(channel, Ok(fuse_getxattr_out { size: value.len() as u32, padding: 0 })),
 					// If arg.size is non-zero, send the value if it fits, or ERANGE otherwise
-					Ok(ref value) if value.len() > arg.size as uint => self.reply_error(ERANGE),
-					Ok(value) => self.reply(Ok(value)),
-					Err(err) => self.reply_error(err),
+					Ok(ref value) if value.len() > arg.size as uint => self.reply_error(channel, ERANGE),
+					Ok(value) => self.reply(channel, Ok(value)),
+					Err(err) => self.reply_error(channel, err),
 				}
 			},
 			FUSE_LISTXATTR => {
 				let arg: &fuse_getxattr_in = data.fetch();
 				debug2!("LISTXATTR({:u}) ino {:#018x}, size {:u}", header.unique, header.nodeid, arg.size);
 				match se.filesystem.listxattr(header.nodeid) {
-					// TODO: If arg.size is zero, the size of the attribute list should be sent with fuse_getxattr_out
-					// TODO: If arg.size is non-zero, send the attribute list if it fits, or ERANGE otherwise
-					Ok(_) => self.reply_error(ENOSYS),
-					Err(err) => self.reply_error(err),
+					// If arg.size is zero, the size of the attribute list should be sent with fuse_getxattr_out
+					Ok(ref list) if arg.size == 0 => self.reply(channel, Ok(fuse_getxattr_out { size: list.len() as u32, padding: 0 })),
+					// If arg.size is non-zero, send the attribute list if it fits, or ERANGE otherwise
+					Ok(ref list) if list.len() > arg.size as uint => self.reply_error(channel, ERANGE),
+					Ok(list) => self.reply(channel, Ok(list)),
+					Err(err) => self.reply_error(channel, err),
 				}
 			},
 			FUSE_REMOVEXATTR => {
 				let name = data.fetch_str();
 				debug2!("REMOVEXATTR({:u}) ino {:#018x}, name {:s}", header.unique, header.nodeid, name);
-				self.reply(se.filesystem.removexattr(header.nodeid, name));
+				self.reply(channel, se.filesystem.removexattr(header.nodeid, name));
 			},
 			FUSE_ACCESS => {
 				let arg: &fuse_access_in = data.fetch();
 				debug2!("ACCESS({:u}) ino {:#018x}, mask {:#05o}", header.unique, header.nodeid, arg.mask);
-				self.reply(se.filesystem.access(header.nodeid, arg.mask as uint));
+				self.reply(channel, se.filesystem.access(header.nodeid, arg.mask as uint));
+			},
+			FUSE_CREATE => {
+				let arg: &fuse_create_in = data.fetch();
+				let name = data.fetch_str();
+				debug2!("CREATE({:u}) parent {:#018x}, name {:s}, mode {:#05o}, flags {:#x}", header.unique, header.nodeid, name, arg.mode, arg.flags);
+				match se.filesystem.create(header.nodeid, name, arg.mode as mode_t, arg.umask as mode_t, arg.flags as uint) {
+					Ok((entry, open)) => self.reply(channel, Ok(CreateReply { entry: entry, open: open })),
+					Err(ENOSYS) => {
+						// Remember that this filesystem doesn't support atomic create-and-open,
+						// so the kernel can fall back to its MKNOD+OPEN sequence instead
+						se.no_create = true;
+						self.reply_error(channel, ENOSYS);
+					},
+					Err(err) => self.reply_error(channel, err),
+				}
+			},
+			FUSE_GETLK => {
+				let arg: &fuse_lk_in = data.fetch();
+				debug2!("GETLK({:u}) ino {:#018x}, fh {:u}, owner {:u}", header.unique, header.nodeid, arg.fh, arg.owner);
+				self.reply(channel, se.filesystem.getlk(header.nodeid, arg.fh, arg.owner, &arg.lk, arg.lk_flags as uint));
+			},
+			FUSE_SETLK => {
+				let arg: &fuse_lk_in = data.fetch();
+				debug2!("SETLK({:u}) ino {:#018x}, fh {:u}, owner {:u}", header.unique, header.nodeid, arg.fh, arg.owner);
+				self.reply(channel, se.filesystem.setlk(header.nodeid, arg.fh, arg.owner, &arg.lk, arg.lk_flags as uint, false));
+			},
+			FUSE_SETLKW => {
+				let arg: &fuse_lk_in = data.fetch();
+				debug2!("SETLKW({:u}) ino {:#018x}, fh {:u}, owner {:u}", header.unique, header.nodeid, arg.fh, arg.owner);
+				self.reply(channel, se.filesystem.setlk(header.nodeid, arg.fh, arg.owner, &arg.lk, arg.lk_flags as uint, true));
+			},
+			FUSE_IOCTL => {
+				let arg: &fuse_ioctl_in = data.fetch();
+				let input = data.fetch_data();
+				assert!(input.len() == arg.in_size as uint);
+				debug2!("IOCTL({:u}) ino {:#018x}, fh {:u}, flags {:#x}, cmd {:#x}, in_size {:u}, out_size {:u}", header.unique, header.nodeid, arg.fh, arg.flags, arg.cmd, arg.in_size, arg.out_size);
+				let unrestricted = match arg.flags & FUSE_IOCTL_UNRESTRICTED { 0 => false, _ => true };
+				match se.filesystem.ioctl(header.nodeid, arg.fh, arg.flags as uint, arg.cmd as uint, arg.arg, input, arg.out_size as uint) {
+					Ok(Done(result, out)) => {
+						let reply = fuse_ioctl_out { result: result, flags: 0, in_iovs: 0, out_iovs: 0 };
+						self.reply(channel, Ok(IoctlDataReply { header: reply, data: out }));
+					},
+					Ok(Retry(in_iovs, out_iovs)) if unrestricted => {
+						let reply = fuse_ioctl_out { result: 0, flags: FUSE_IOCTL_RETRY, in_iovs: in_iovs.len() as u32, out_iovs: out_iovs.len() as u32 };
+						self.reply(channel, Ok(IoctlRetryReply { header: reply, in_iovs: in_iovs, out_iovs: out_iovs }));
+					},
+					Ok(Retry(_, _)) => {
+						// Retry replies are only allowed when the kernel marked the ioctl unrestricted
+						self.reply_error(channel, EINVAL);
+					},
+					Err(err) => self.reply_error(channel, err),
+				}
 			},
-			// TODO: FUSE_CREATE,
-			// TODO: FUSE_GETLK,
-			// TODO: FUSE_SETLK,
-			// TODO: FUSE_SETLKW,
 			FUSE_BMAP => {
 				let arg: &fuse_bmap_in = data.fetch();
 				debug2!("BMAP({:u}) ino {:#018x}, blocksize {:u}, ids {:u}", header.unique, header.nodeid, arg.blocksize, arg.block);
-				self.reply(se.filesystem.bmap(header.nodeid, arg.blocksize as size_t, arg.block));
+				self.reply(channel, se.filesystem.bmap(header.nodeid, arg.blocksize as size_t, arg.block));
 			},
 			FUSE_SETVOLNAME => {			// OS X only
 				let name = data.fetch_str();
 				debug2!("SETVOLNAME({:u}) name {:s}", header.unique, name);
-				self.reply(se.filesystem.setvolname(name));
+				self.reply(channel, se.filesystem.setvolname(name));
 			},
 			FUSE_EXCHANGE => {				// OS X only
 				let arg: &fuse_exchange_in = data.fetch();
 				let oldname = data.fetch_str();
 				let newname = data.fetch_str();
 				debug2!("EXCHANGE({:u}) parent {:#018x}, name {:s}, newparent {:#018x}, newname {:s}, options {:#x}", header.unique, arg.olddir, oldname, arg.newdir, newname, arg.options);
-				self.reply(se.filesystem.exchange(arg.olddir, oldname, arg.newdir, newname, arg.options as uint));
+				self.reply(channel, se.filesystem.exchange(arg.olddir, oldname, arg.newdir, newname, arg.options as uint));
 			},
 			FUSE_GETXTIMES => {				// OS X only
 				debug2!("GETXTIMES({:u}) ino {:#018x}", header.unique, header.nodeid);
-				self.reply(se.filesystem.getxtimes(header.nodeid));
+				self.reply(channel, se.filesystem.getxtimes(header.nodeid));
 			},
 
 			_ => {
 				warn2!("Ignoring unsupported FUSE operation {:u}", header.opcode)
-				self.reply_error(ENOSYS);
+				self.reply_error(channel, ENOSYS);
 			},
 		}
+		se.pending.remove(&header.unique);
 	}
 
 	/// Reply to a request with the given error code and data
 	#[fixed_stack_segment]
-	fn send<T: Sendable> (&self, err: c_int, data: &T) {
+	fn send<T: Sendable, C: ChannelWriter> (&self, channel: &mut C, err: c_int, data: &T) {
 		let header: &fuse_in_header = ArgumentIterator::new(self.data).fetch();
 		do data.as_iovecs |data_iovs| {
 			let len = data_iovs.iter().fold(0u32, |l, iov| { l + iov.iov_len as u32 });
@@ -354,21 +628,21 @@ impl Request {
 				iov_len: sys::size_of_val(&header) as size_t,
 			}] + data_iovs;
 			do iov.as_imm_buf |iovptr, iovlen| {
-				unsafe { writev(self.fd.unwrap(), iovptr, iovlen as c_int); }
+				unsafe { channel.send_reply(iovptr, iovlen as c_int); }
 			}
 		}
 	}
 
 	/// Reply to a request with the given data or error code
-	fn reply<T: Sendable> (&self, result: Result<T, c_int>) {
+	fn reply<T: Sendable, C: ChannelWriter> (&self, channel: &mut C, result: Result<T, c_int>) {
 		match result {
-			Ok(reply) => self.send(0, &reply),
-			Err(err) => self.send(-err, &()),
+			Ok(reply) => self.send(channel, 0, &reply),
+			Err(err) => self.send(channel, -err, &()),
 		}
 	}
 
 	/// Reply to a request with the given error code
-	fn reply_error (&self, err: c_int) {
-		self.send(-err, &());
+	fn reply_error<C: ChannelWriter> (&self, channel: &mut C, err: c_int) {
+		self.send(channel, -err, &());
 	}
 }